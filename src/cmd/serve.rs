@@ -18,6 +18,66 @@ use warp::Filter;
 /// The HTTP endpoint for the websocket used to trigger reloads when a file changes.
 const LIVE_RELOAD_ENDPOINT: &str = "__livereload";
 
+/// A minimal livereload client, served at `/{LIVE_RELOAD_ENDPOINT}/livereload.js`.
+///
+/// It connects to `ws_url` — the same advertised `livereload-url` the theme
+/// snippet uses, so it keeps working when `--websocket-port`/`--websocket-hostname`
+/// point the socket at a host or port the page itself is not served on — and
+/// reloads the document on any message, reconnecting if the connection drops.
+fn livereload_script(ws_url: &str) -> String {
+    format!(
+        r#"(function () {{
+    function connect() {{
+        var socket = new WebSocket("{ws_url}");
+        socket.onmessage = function () {{
+            window.location.reload();
+        }};
+        socket.onclose = function () {{
+            setTimeout(connect, 1000);
+        }};
+    }}
+    connect();
+}})();
+"#
+    )
+}
+
+/// The `<script>` tag injected into served HTML that loads the standalone
+/// livereload client above.
+const LIVE_RELOAD_SCRIPT_TAG: &str = "<script src=\"/__livereload/livereload.js\"></script>";
+
+/// Injects the livereload `<script>` tag just before `</body>`, so pages whose
+/// theme did not embed the websocket snippet still reference the client and
+/// reconnect on changes.
+///
+/// Pages that already reference the livereload endpoint — the default theme
+/// emits its own snippet once `output.html.livereload-url` is set — are left
+/// untouched, so they do not end up with two sockets reloading twice per change.
+fn inject_livereload(html: String) -> String {
+    if html.contains(LIVE_RELOAD_ENDPOINT) {
+        return html;
+    }
+    match html.rfind("</body>") {
+        Some(pos) => {
+            let mut out = String::with_capacity(html.len() + LIVE_RELOAD_SCRIPT_TAG.len());
+            out.push_str(&html[..pos]);
+            out.push_str(LIVE_RELOAD_SCRIPT_TAG);
+            out.push_str(&html[pos..]);
+            out
+        }
+        None => html + LIVE_RELOAD_SCRIPT_TAG,
+    }
+}
+
+/// A rejection carrying the path of a request that matched no route, so the
+/// recovery handler can pick the localized 404 page for that path.
+#[derive(Debug)]
+struct MissingPage {
+    path: String,
+}
+
+impl warp::reject::Reject for MissingPage {}
+
 // Create clap subcommand arguments
 pub fn make_subcommand<'a, 'b>() -> App<'a, 'b> {
     SubCommand::with_name("serve")
@@ -49,6 +109,48 @@ pub fn make_subcommand<'a, 'b>() -> App<'a, 'b> {
                 .empty_values(false)
                 .help("Port to use for HTTP connections"),
         )
+        .arg(
+            Arg::with_name("websocket-port")
+                .short("w")
+                .long("websocket-port")
+                .takes_value(true)
+                .empty_values(false)
+                .help(
+                    "Port to use for the live reload websocket.{n}\
+                     Defaults to the HTTP port. Set this when the reload socket \
+                     must be reachable on a different port than the book itself, \
+                     e.g. behind a TLS-terminating reverse proxy.",
+                ),
+        )
+        .arg(
+            Arg::with_name("websocket-hostname")
+                .short("a")
+                .long("websocket-hostname")
+                .takes_value(true)
+                .empty_values(false)
+                .help(
+                    "Externally reachable hostname advertised to the browser for \
+                     the live reload websocket.{n}\
+                     Defaults to the HTTP hostname. Set this when the browser \
+                     reaches the socket through a different host than the one it \
+                     is bound on.",
+                ),
+        )
+        .arg(
+            Arg::with_name("websocket-scheme")
+                .long("websocket-scheme")
+                .takes_value(true)
+                .possible_values(&["ws", "wss"])
+                .default_value("ws")
+                .empty_values(false)
+                .help(
+                    "URI scheme advertised to the browser for the live reload \
+                     websocket.{n}\
+                     Set this to `wss` behind a TLS-terminating reverse proxy, so \
+                     an `https` page does not get blocked connecting to a `ws` \
+                     socket as mixed content.",
+                ),
+        )
         .arg_from_usage("-o, --open 'Opens the book server in a web browser'")
         .arg_from_usage(
             "-l, --language=[language] 'Language to render the compiled book in.{n}\
@@ -67,20 +169,26 @@ pub fn execute(args: &ArgMatches) -> Result<()> {
     let hostname = args.value_of("hostname").unwrap();
     let open_browser = args.is_present("open");
 
+    // The live reload socket may run on a different port and advertise a
+    // different externally reachable host than the HTTP server, e.g. behind a
+    // reverse proxy. Both default to the HTTP hostname/port.
+    let ws_port = args.value_of("websocket-port").unwrap_or(port);
+    let ws_hostname = args.value_of("websocket-hostname").unwrap_or(hostname);
+    // Advertise `wss://` behind a TLS-terminating proxy so an `https` page is not
+    // blocked connecting to the socket as mixed content.
+    let ws_scheme = args.value_of("websocket-scheme").unwrap();
+
     let address = format!("{}:{}", hostname, port);
 
-    let livereload_url = format!("ws://{}/{}", address, LIVE_RELOAD_ENDPOINT);
-    let update_config = |book: &mut MDBook| {
-        book.config
-            .set("output.html.livereload-url", &livereload_url)
-            .expect("livereload-url update failed");
-        if let Some(dest_dir) = args.value_of("dest-dir") {
-            book.config.build.build_dir = dest_dir.into();
-        }
-        // Override site-url for local serving of the 404 file
-        book.config.set("output.html.site-url", "/").unwrap();
-    };
-    update_config(&mut book);
+    let livereload_url = format!(
+        "{}://{}:{}/{}",
+        ws_scheme, ws_hostname, ws_port, LIVE_RELOAD_ENDPOINT
+    );
+    // Owned so the watch loop — which runs on its own thread (see below) — can
+    // re-apply the config on every rebuild without borrowing `args`.
+    let dest_dir = args.value_of("dest-dir").map(PathBuf::from);
+
+    update_config(&mut book, &livereload_url, dest_dir.as_deref());
     book.build()?;
 
     let language: Option<String> = match build_opts.language_ident {
@@ -99,6 +207,22 @@ pub fn execute(args: &ArgMatches) -> Result<()> {
         .to_socket_addrs()?
         .next()
         .ok_or_else(|| anyhow::anyhow!("no address found for {}", address))?;
+
+    // When the websocket runs on a different port, bind it on its own address
+    // so the reload filter is also served by a separate server there; it stays
+    // attached to the HTTP routes in either case.
+    let ws_sockaddr: Option<SocketAddr> = if ws_port != port {
+        let ws_address = format!("{}:{}", hostname, ws_port);
+        Some(
+            ws_address
+                .to_socket_addrs()?
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("no address found for {}", ws_address))?,
+        )
+    } else {
+        None
+    };
+
     let build_dir = book.build_dir_for("html");
     let input_404 = book
         .config
@@ -111,9 +235,23 @@ pub fn execute(args: &ArgMatches) -> Result<()> {
     // A channel used to broadcast to any websockets to reload when a file changes.
     let (tx, _rx) = tokio::sync::broadcast::channel::<Message>(100);
 
+    // Lets the server thread tell the main thread it has drained and exited once
+    // Ctrl+C is received, so the main thread can stop waiting and join it.
+    let (shutdown_tx, shutdown_rx) = std::sync::mpsc::channel::<()>();
+
     let reload_tx = tx.clone();
+    let ws_livereload_url = livereload_url.clone();
     let thread_handle = std::thread::spawn(move || {
-        serve(build_dir, sockaddr, reload_tx, &file_404, language);
+        serve(
+            build_dir,
+            sockaddr,
+            ws_sockaddr,
+            reload_tx,
+            shutdown_tx,
+            ws_livereload_url,
+            &file_404,
+            language,
+        );
     });
 
     let serving_url = format!("http://{}", address);
@@ -123,67 +261,133 @@ pub fn execute(args: &ArgMatches) -> Result<()> {
         open(serving_url);
     }
 
+    // The watch loop blocks forever, so it runs on its own thread rather than the
+    // main thread. That keeps the main thread free to wait for the shutdown
+    // signal and then join the server thread below, instead of getting stuck in
+    // `trigger_on_change` and never unwinding on Ctrl+C.
     #[cfg(feature = "watch")]
-    watch::trigger_on_change(&book, move |paths, book_dir| {
-        info!("Files changed: {:?}", paths);
-        info!("Building book...");
-
-        // FIXME: This area is really ugly because we need to re-set livereload :(
-        let result =
-            MDBook::load_with_build_opts(&book_dir, build_opts.clone()).and_then(|mut b| {
-                update_config(&mut b);
-                b.build()
-            });
-
-        if let Err(e) = result {
-            error!("Unable to load the book");
-            utils::log_backtrace(&e);
-        } else {
-            let _ = tx.send(Message::text("reload"));
-        }
+    std::thread::spawn(move || {
+        watch::trigger_on_change(&book, move |paths, book_dir| {
+            info!("Files changed: {:?}", paths);
+            info!("Building book...");
+
+            // FIXME: This area is really ugly because we need to re-set livereload :(
+            let result =
+                MDBook::load_with_build_opts(&book_dir, build_opts.clone()).and_then(|mut b| {
+                    update_config(&mut b, &livereload_url, dest_dir.as_deref());
+                    b.build()
+                });
+
+            if let Err(e) = result {
+                error!("Unable to load the book");
+                utils::log_backtrace(&e);
+            } else {
+                let _ = tx.send(Message::text("reload"));
+            }
+        });
     });
 
+    // Wait until the server has shut down on Ctrl+C, then join it so in-flight
+    // requests have drained before returning. Returning tears down the detached
+    // watch thread and exits the process, restoring the pre-existing behaviour of
+    // quitting on SIGINT.
+    let _ = shutdown_rx.recv();
     let _ = thread_handle.join();
 
     Ok(())
 }
 
+/// Re-applies the serve-time config overrides to `book`: points the theme's
+/// livereload snippet at `livereload_url`, optionally redirects the build output
+/// to `dest_dir`, and pins `site-url` to `/` so the local 404 page resolves.
+fn update_config(book: &mut MDBook, livereload_url: &str, dest_dir: Option<&std::path::Path>) {
+    book.config
+        .set("output.html.livereload-url", livereload_url)
+        .expect("livereload-url update failed");
+    if let Some(dest_dir) = dest_dir {
+        book.config.build.build_dir = dest_dir.into();
+    }
+    // Override site-url for local serving of the 404 file
+    book.config.set("output.html.site-url", "/").unwrap();
+}
+
 #[tokio::main]
 async fn serve(
     build_dir: PathBuf,
     address: SocketAddr,
+    ws_address: Option<SocketAddr>,
     reload_tx: broadcast::Sender<Message>,
+    shutdown_tx: std::sync::mpsc::Sender<()>,
+    livereload_url: String,
     file_404: &str,
     language: Option<String>,
 ) {
-    // A warp Filter which captures `reload_tx` and provides an `rx` copy to
-    // receive reload messages.
-    let sender = warp::any().map(move || reload_tx.subscribe());
+    // A warp Filter to handle the livereload endpoint. When the websocket is
+    // configured to run on its own port, bind it on a dedicated server there so
+    // the HTTP book can live behind a reverse proxy that does not forward the
+    // socket.
+    let livereload = livereload_filter(reload_tx.clone());
+    if let Some(ws_address) = ws_address {
+        let ws_livereload = livereload_filter(reload_tx.clone());
+        let (_, ws_server) = warp::serve(ws_livereload)
+            .bind_with_graceful_shutdown(ws_address, shutdown_signal());
+        tokio::task::spawn(ws_server);
+    }
 
-    // A warp Filter to handle the livereload endpoint. This upgrades to a
-    // websocket, and then waits for any filesystem change notifications, and
-    // relays them over the websocket.
-    let livereload = warp::path(LIVE_RELOAD_ENDPOINT)
-        .and(warp::ws())
-        .and(sender)
-        .map(|ws: warp::ws::Ws, mut rx: broadcast::Receiver<Message>| {
-            ws.on_upgrade(move |ws| async move {
-                let (mut user_ws_tx, _user_ws_rx) = ws.split();
-                trace!("websocket got connection");
-                if let Ok(m) = rx.recv().await {
-                    trace!("notify of reload");
-                    let _ = user_ws_tx.send(m).await;
+    // A warp Filter serving a tiny standalone livereload client. Themes that do
+    // not inject the websocket snippet themselves can load this script to get
+    // live reload for free, mirroring how the Zola server embeds livereload-js.
+    let script = livereload_script(&livereload_url);
+    let livereload_js = warp::path(LIVE_RELOAD_ENDPOINT)
+        .and(warp::path("livereload.js"))
+        .and(warp::path::end())
+        .map(move || {
+            warp::reply::with_header(
+                script.clone(),
+                "content-type",
+                "application/javascript",
+            )
+        });
+
+    // Serve HTML pages with the livereload `<script>` tag injected, so themes
+    // that did not embed their own snippet still reference `livereload.js` and
+    // reconnect on changes. Non-HTML requests fall through to the static file
+    // handler below.
+    let pages_dir = build_dir.clone();
+    let pages = warp::get()
+        .and(warp::path::full())
+        .and_then(move |path: warp::path::FullPath| {
+            let pages_dir = pages_dir.clone();
+            async move {
+                let rel = path.as_str().trim_start_matches('/');
+                // `warp::path::full()` does not normalize the path, so reject any
+                // request with a `..` (or other non-normal) segment before joining
+                // to avoid escaping `build_dir`, the sanitization `warp::fs::dir`
+                // would otherwise provide.
+                if std::path::Path::new(rel).components().any(|c| {
+                    !matches!(
+                        c,
+                        std::path::Component::Normal(_) | std::path::Component::CurDir
+                    )
+                }) {
+                    return Err(warp::reject::not_found());
                 }
-            })
+                let mut file = pages_dir.join(rel);
+                if rel.is_empty() || path.as_str().ends_with('/') {
+                    file.push("index.html");
+                }
+                if file.extension().and_then(|e| e.to_str()) != Some("html") {
+                    return Err(warp::reject::not_found());
+                }
+                match tokio::fs::read_to_string(&file).await {
+                    Ok(contents) => Ok(warp::reply::html(inject_livereload(contents))),
+                    Err(_) => Err(warp::reject::not_found()),
+                }
+            }
         });
-    // A warp Filter that serves from the filesystem.
-    let book_route = warp::fs::dir(build_dir.clone());
 
-    std::panic::set_hook(Box::new(move |panic_info| {
-        // exit if serve panics
-        error!("Unable to serve: {}", panic_info);
-        std::process::exit(1);
-    }));
+    // A warp Filter that serves the remaining (non-HTML) assets from the filesystem.
+    let book_route = warp::fs::dir(build_dir.clone());
 
     if let Some(lang_ident) = language {
         // Redirect root to the default translation directory, if serving a localized book.
@@ -197,24 +401,142 @@ async fn serve(
         let redirect_to_index =
             warp::path::end().map(move || warp::redirect(index_for_language.clone()));
 
-        // BUG: It is not possible to conditionally redirect to the correct 404
-        // page depending on the URL using warp, so just redirect to the one in
-        // the default language.
-        // See: https://github.com/seanmonstar/warp/issues/171
-        let fallback_route = warp::fs::file(build_dir.join(lang_ident).join(file_404))
-            .map(|reply| warp::reply::with_status(reply, warp::http::StatusCode::NOT_FOUND));
+        // A catch-all that rejects with the requested path so the recovery
+        // handler below can serve the 404 page for the right language.
+        let not_found = warp::path::full().and_then(|path: warp::path::FullPath| async move {
+            Err::<warp::reply::Response, warp::Rejection>(warp::reject::custom(MissingPage {
+                path: path.as_str().to_owned(),
+            }))
+        });
+
+        // Serve the 404 page of the language the missing URL belongs to (e.g.
+        // `/de/...` gets `de`'s 404), falling back to the default language when
+        // the leading path segment is not a known translation. This replaces
+        // the previous behaviour of always serving the default language's 404.
+        let build_dir = build_dir.clone();
+        let file_404 = file_404.to_owned();
+        let default_lang = lang_ident.clone();
+        let recover_404 = move |err: warp::Rejection| {
+            let build_dir = build_dir.clone();
+            let file_404 = file_404.clone();
+            let default_lang = default_lang.clone();
+            async move {
+                if let Some(missing) = err.find::<MissingPage>() {
+                    let segment = missing
+                        .path
+                        .trim_start_matches('/')
+                        .split('/')
+                        .next()
+                        .unwrap_or("");
+                    // Use the leading segment's 404 page when it has one, falling
+                    // back to the default language otherwise. The existence check
+                    // is async so it never blocks the runtime thread.
+                    let lang = if !segment.is_empty()
+                        && tokio::fs::metadata(build_dir.join(segment).join(&file_404))
+                            .await
+                            .map(|m| m.is_file())
+                            .unwrap_or(false)
+                    {
+                        segment.to_string()
+                    } else {
+                        default_lang
+                    };
+                    let body = tokio::fs::read_to_string(build_dir.join(lang).join(&file_404))
+                        .await
+                        .unwrap_or_default();
+                    Ok(warp::reply::with_status(
+                        warp::reply::html(body),
+                        warp::http::StatusCode::NOT_FOUND,
+                    ))
+                } else {
+                    Err(err)
+                }
+            }
+        };
 
         let routes = livereload
+            .or(livereload_js)
             .or(redirect_to_index)
+            .or(pages)
             .or(book_route)
-            .or(fallback_route);
-        warp::serve(routes).run(address).await;
+            .or(not_found)
+            .recover(recover_404);
+        let (_, server) =
+            warp::serve(routes).bind_with_graceful_shutdown(address, shutdown_signal());
+        server.await;
     } else {
         // The fallback route for 404 errors
         let fallback_route = warp::fs::file(build_dir.join(file_404))
             .map(|reply| warp::reply::with_status(reply, warp::http::StatusCode::NOT_FOUND));
 
-        let routes = livereload.or(book_route).or(fallback_route);
-        warp::serve(routes).run(address).await;
+        let routes = livereload
+            .or(livereload_js)
+            .or(pages)
+            .or(book_route)
+            .or(fallback_route);
+        let (_, server) =
+            warp::serve(routes).bind_with_graceful_shutdown(address, shutdown_signal());
+        server.await;
     };
+
+    // The server has drained and stopped (Ctrl+C); let `execute` unblock and join
+    // this thread.
+    let _ = shutdown_tx.send(());
+}
+
+/// Builds the warp Filter handling the livereload endpoint. It upgrades to a
+/// websocket and then forwards every filesystem-change notification received on
+/// `reload_tx` for the lifetime of the connection.
+fn livereload_filter(
+    reload_tx: broadcast::Sender<Message>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    // A warp Filter which captures `reload_tx` and provides an `rx` copy to
+    // receive reload messages.
+    let sender = warp::any().map(move || reload_tx.subscribe());
+
+    warp::path(LIVE_RELOAD_ENDPOINT)
+        .and(warp::ws())
+        .and(sender)
+        .map(|ws: warp::ws::Ws, mut rx: broadcast::Receiver<Message>| {
+            ws.on_upgrade(move |ws| async move {
+                let (mut user_ws_tx, _user_ws_rx) = ws.split();
+                trace!("websocket got connection");
+                // Keep the socket open and forward every reload message for the
+                // lifetime of the connection, so fast successive rebuilds all
+                // reach the browser instead of dropping after a single reload.
+                loop {
+                    match rx.recv().await {
+                        Ok(m) => {
+                            trace!("notify of reload");
+                            if user_ws_tx.send(m).await.is_err() {
+                                // The client disconnected.
+                                break;
+                            }
+                        }
+                        // We fell behind the broadcast buffer. Coalesce the
+                        // missed notifications into a single reload and keep
+                        // listening rather than tearing down the socket.
+                        Err(broadcast::error::RecvError::Lagged(_)) => {
+                            trace!("websocket lagged, sending coalesced reload");
+                            if user_ws_tx.send(Message::text("reload")).await.is_err() {
+                                break;
+                            }
+                        }
+                        // The sender was dropped; the server is shutting down.
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    }
+                }
+            })
+        })
+}
+
+/// Resolves once a `SIGINT`/`Ctrl+C` is received, signalling the warp server to
+/// stop accepting new connections and drain in-flight requests before the server
+/// thread is joined in `execute`. The watch thread is not joined; it is left
+/// running until the process exits.
+async fn shutdown_signal() {
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        error!("Unable to listen for shutdown signal: {}", e);
+    }
+    info!("Shutting down the server");
 }